@@ -9,6 +9,7 @@ use samp::error::{AmxError, AmxResult};
 use samp::plugin::SampPlugin;
 use scheduling::{reschedule_next_due_and_then, reschedule_timer};
 
+use std::cell::Cell;
 use std::convert::TryFrom;
 use timer::Timer;
 mod amx_arguments;
@@ -17,7 +18,13 @@ mod scheduling;
 mod timer;
 use schedule::Repeat::{DontRepeat, Every};
 use schedule::Schedule;
-use scheduling::{delete_timer, insert_and_schedule_timer, remove_timers};
+use scheduling::{delete_timer, insert_and_schedule_timer, remove_timers, ProductionContext};
+
+thread_local! {
+    /// Maximum number of due timers `process_tick` will fire in a single tick.
+    /// `0` means unlimited, preserving the historical behaviour of draining every due timer at once.
+    static TICK_BUDGET: Cell<usize> = const { Cell::new(0) };
+}
 
 /// The plugin
 struct PreciseTimers;
@@ -47,7 +54,7 @@ impl PreciseTimers {
             amx: amx.clone(),
             amx_callback_index: amx.find_public(&callback_name.to_string())?,
         };
-        let key = insert_and_schedule_timer(timer, |_key| Schedule {
+        let key = insert_and_schedule_timer(&ProductionContext, timer, |_key| Schedule {
             next_trigger: now() + interval,
             repeat: if repeat { Every(interval) } else { DontRepeat },
         });
@@ -67,7 +74,7 @@ impl PreciseTimers {
     #[samp::native(name = "DeletePreciseTimer")]
     pub fn delete(&self, _: &Amx, timer_number: usize) -> AmxResult<i32> {
         let key = timer_number - 1;
-        if let Err(err) = delete_timer(key) {
+        if let Err(err) = delete_timer(&ProductionContext, key) {
             error!("{err}");
             return Ok(0);
         }
@@ -96,12 +103,26 @@ impl PreciseTimers {
             next_trigger: now() + interval,
             repeat: if repeat { Every(interval) } else { DontRepeat },
         };
-        if let Err(error) = reschedule_timer(key, schedule) {
+        if let Err(error) = reschedule_timer(&ProductionContext, key, schedule) {
             error!("{error}");
             return Ok(0);
         }
         Ok(1)
     }
+
+    /// This function is called from PAWN via the C foreign function interface.
+    /// Caps how many due timers `process_tick` fires per tick, so that a large
+    /// batch of timers coming due on the same tick cannot stall the main thread.
+    /// `0` disables the cap (the default), firing every due timer as before.
+    ///  ```
+    /// native SetPreciseTimerTickBudget(max_per_tick)
+    /// ```
+    #[samp::native(name = "SetPreciseTimerTickBudget")]
+    pub fn set_tick_budget(&self, _: &Amx, max_per_tick: i32) -> AmxResult<i32> {
+        let budget = usize::try_from(max_per_tick).map_err(|_| AmxError::Params)?;
+        TICK_BUDGET.set(budget);
+        Ok(1)
+    }
 }
 
 impl SampPlugin for PreciseTimers {
@@ -110,15 +131,24 @@ impl SampPlugin for PreciseTimers {
     }
 
     fn on_amx_unload(&self, unloaded_amx: &Amx) {
-        remove_timers(|timer| timer.was_scheduled_by_amx(unloaded_amx));
+        remove_timers(&ProductionContext, |timer| {
+            timer.was_scheduled_by_amx(unloaded_amx)
+        });
     }
 
     #[allow(clippy::inline_always)]
     #[inline(always)]
     fn process_tick(&self) {
-        let now = now();
-
-        while let Some(callback) = reschedule_next_due_and_then(now, Timer::stack_callback_on_amx) {
+        let budget = TICK_BUDGET.get();
+        let mut fired = 0usize;
+
+        while budget == 0 || fired < budget {
+            let Some(callback) =
+                reschedule_next_due_and_then(&ProductionContext, Timer::stack_callback_on_amx)
+            else {
+                break;
+            };
+            fired += 1;
             match callback {
                 Ok(stacked_callback) => {
                     // SAFETY: We are not holding any references to scheduling stores.
@@ -137,6 +167,7 @@ samp::initialize_plugin!(
         PreciseTimers::delete,
         PreciseTimers::create,
         PreciseTimers::reset,
+        PreciseTimers::set_tick_budget,
     ],
     {
         samp::plugin::enable_process_tick();
@@ -1,6 +1,6 @@
 use std::{
     cell::{Cell, RefCell},
-    rc::{Rc, Weak},
+    collections::BinaryHeap,
     time::Instant,
 };
 
@@ -13,21 +13,68 @@ use crate::{schedule::Schedule, timer::Timer};
 struct TimerState {
     timer: Timer,
     schedule: Cell<Schedule>,
+    /// Set to a fresh value from `State::next_generation` every time this timer is
+    /// (re)scheduled, so that stale heap entries left behind by a reschedule can be
+    /// recognised and discarded lazily, even if the timer's `Slab` key gets reused
+    /// by an unrelated timer after a delete.
+    generation: Cell<u64>,
+}
+
+/// An entry in the heap. Ordered by `schedule` first (via `Schedule`'s `Ord`, which
+/// orders soonest-due first); `key`/`generation` identify which timer it is for and
+/// only break ties between entries due at the same instant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct HeapEntry {
+    schedule: Schedule,
     key: usize,
+    generation: u64,
+}
+
+pub(crate) struct State {
+    /// A slotmap of timers, also holding each timer's current generation. Stable keys.
+    timers: Slab<TimerState>,
+    /// Min-heap of scheduled timers, keyed by due time. Entries become stale when their
+    /// timer is deleted or rescheduled; stale entries are discarded lazily when popped.
+    heap: BinaryHeap<HeapEntry>,
+    /// Monotonic counter handed out as each timer's generation. Never reused, so a stale
+    /// heap entry can't be mistaken for a newer timer that happens to land on the same
+    /// `Slab` key after a delete frees it for reuse.
+    next_generation: u64,
+}
+
+impl State {
+    fn new() -> Self {
+        State {
+            timers: Slab::with_capacity(1000),
+            heap: BinaryHeap::with_capacity(1000),
+            next_generation: 0,
+        }
+    }
 }
 
-struct State {
-    /// A slotmap of timers. Stable keys.
-    timers: Slab<Weak<TimerState>>,
-    /// Always sorted queue of timers. Easy O(1) peeking and popping of the next scheduled timer.
-    queue: Vec<Rc<TimerState>>,
+/// Gives scheduling functions access to "the current time" and "the timer store"
+/// without hard-coding the production thread-local, so that tests can supply a
+/// fake clock and an isolated store instead.
+pub(crate) trait TimerContext {
+    fn now(&self) -> Instant;
+    fn with_store<R>(&self, f: impl FnOnce(&mut State) -> R) -> R;
 }
 
 thread_local! {
-    static STATE: RefCell<State> = RefCell::new(State {
-        timers: Slab::with_capacity(1000),
-        queue: Vec::with_capacity(1000),
-    })
+    static STATE: RefCell<State> = RefCell::new(State::new());
+}
+
+/// The production context: real wall-clock time and the process-wide thread-local store.
+pub(crate) struct ProductionContext;
+
+impl TimerContext for ProductionContext {
+    fn now(&self) -> Instant {
+        durr::now()
+    }
+
+    fn with_store<R>(&self, f: impl FnOnce(&mut State) -> R) -> R {
+        STATE.with_borrow_mut(f)
+    }
 }
 
 #[derive(Debug, Snafu)]
@@ -38,59 +85,86 @@ pub(crate) enum TriggeringError {
 }
 
 pub(crate) fn insert_and_schedule_timer(
+    ctx: &impl TimerContext,
     timer: Timer,
     get_schedule_based_on_key: impl FnOnce(usize) -> Schedule,
 ) -> usize {
-    STATE.with_borrow_mut(|State { timers, queue }| {
-        let entry = timers.vacant_entry();
-        let key = entry.key();
-        let schedule = get_schedule_based_on_key(key);
-        let new_position = queue.partition_point(|s| s.schedule.get() < schedule);
-        let schedule = Cell::new(schedule);
-        let rc = Rc::new(TimerState { timer, schedule, key });
-        entry.insert(Rc::downgrade(&rc));
-        queue.insert(new_position, rc);
-        key
-    })
+    ctx.with_store(
+        |State {
+             timers,
+             heap,
+             next_generation,
+         }| {
+            let entry = timers.vacant_entry();
+            let key = entry.key();
+            let schedule = get_schedule_based_on_key(key);
+            let generation = *next_generation;
+            *next_generation += 1;
+            entry.insert(TimerState {
+                timer,
+                schedule: Cell::new(schedule),
+                generation: Cell::new(generation),
+            });
+            heap.push(HeapEntry {
+                schedule,
+                key,
+                generation,
+            });
+            key
+        },
+    )
 }
 
-pub(crate) fn delete_timer(timer_key: usize) -> Result<(), TriggeringError> {
-    STATE.with_borrow_mut(|State { timers, queue }| {
+pub(crate) fn delete_timer(
+    ctx: &impl TimerContext,
+    timer_key: usize,
+) -> Result<(), TriggeringError> {
+    ctx.with_store(|State { timers, .. }| {
         ensure!(timers.contains(timer_key), TimerNotInQueue);
         timers.remove(timer_key);
-        queue.retain(|s| s.key != timer_key);
+        // The now-stale heap entry for `timer_key` is discarded lazily once it's popped.
         Ok(())
     })
 }
 
-pub(crate) fn reschedule_timer(key: usize, new_schedule: Schedule) -> Result<(), TriggeringError> {
-    STATE.with_borrow_mut(|State { queue, timers }| {
-        let old_state = timers[key].upgrade().unwrap();
-        let old_index = queue
-            .binary_search_by_key(&old_state.schedule.get(), |ts| ts.schedule.get())
-            .unwrap();
-
-        let new_index = queue.partition_point(|s| s.schedule.get() < new_schedule);
-        queue[old_index].schedule.replace(new_schedule);
-        if new_index < old_index {
-            queue[new_index..=old_index].rotate_right(1);
-        } else if new_index > old_index {
-            queue[old_index..=new_index].rotate_left(1);
-        }
-        Ok(())
-    })
+pub(crate) fn reschedule_timer(
+    ctx: &impl TimerContext,
+    key: usize,
+    new_schedule: Schedule,
+) -> Result<(), TriggeringError> {
+    ctx.with_store(
+        |State {
+             timers,
+             heap,
+             next_generation,
+         }| {
+            ensure!(timers.contains(key), TimerNotInQueue);
+            let state = &timers[key];
+            state.schedule.set(new_schedule);
+            let generation = *next_generation;
+            *next_generation += 1;
+            state.generation.set(generation);
+            heap.push(HeapEntry {
+                schedule: new_schedule,
+                key,
+                generation,
+            });
+            Ok(())
+        },
+    )
 }
 
-pub(crate) fn remove_timers(predicate: impl Fn(&Timer) -> bool) {
-    STATE.with_borrow_mut(|State { timers, queue }| {
-        queue.retain(|timer_state| {
-            if predicate(&timer_state.timer) {
-                timers.remove(timer_state.key);
-                false
-            } else {
-                true
-            }
-        });
+pub(crate) fn remove_timers(ctx: &impl TimerContext, predicate: impl Fn(&Timer) -> bool) {
+    ctx.with_store(|State { timers, .. }| {
+        let keys_to_remove: Vec<usize> = timers
+            .iter()
+            .filter(|(_, state)| predicate(&state.timer))
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys_to_remove {
+            timers.remove(key);
+            // Stale heap entries for these keys are discarded lazily once popped.
+        }
     });
 }
 
@@ -102,51 +176,101 @@ pub(crate) fn remove_timers(predicate: impl Fn(&Timer) -> bool) {
 /// `timer_manipulator` must not borrow state
 #[inline]
 pub(crate) fn reschedule_next_due_and_then<T>(
-    now: Instant,
+    ctx: &impl TimerContext,
     stack_callback: impl FnOnce(&Timer) -> T,
 ) -> Option<T> {
-    STATE.with_borrow_mut(|State { timers, queue }| {
-        let next_up = queue.last()?;
-        let Schedule { next_trigger, repeat } = next_up.schedule.get();
-        if next_trigger > now {
-            return None;
-        }
-        if let Some(interval) = repeat {
-            let stacked_callback = stack_callback(&next_up.timer);
-
-            let next_trigger = now + interval;
-            let new_schedule = Schedule { next_trigger, repeat };
-            let old_position = queue.len() - 1; // next timer is at the end of the queue
-            let new_position = queue.partition_point(|s| s.schedule.get() >= new_schedule);
-
-            next_up.schedule.replace(new_schedule);
-
-            if new_position < old_position {
-                queue[new_position..].rotate_right(1);
-            } else {
-                debug_assert_eq!(new_position, old_position);
+    let now = ctx.now();
+    ctx.with_store(
+        |State {
+             timers,
+             heap,
+             next_generation,
+         }| loop {
+            let HeapEntry {
+                schedule,
+                key,
+                generation,
+            } = *heap.peek()?;
+            let Some(state) = timers.get(key) else {
+                heap.pop(); // stale: the timer was deleted since this entry was pushed
+                continue;
+            };
+            if state.generation.get() != generation {
+                heap.pop(); // stale: the timer was rescheduled since this entry was pushed
+                continue;
             }
-            Some(stacked_callback)
-        } else {
-            let unscheduled = queue.pop().expect("due timer should be in queue");
-            timers.remove(unscheduled.key);
-
-            Some(stack_callback(&unscheduled.timer))
-        }
-    })
+            if schedule.next_trigger > now {
+                return None;
+            }
+            heap.pop();
+            if let Some(interval) = schedule.repeat {
+                let stacked_callback = stack_callback(&state.timer);
+                let next_trigger = now + interval;
+                let new_schedule = Schedule {
+                    next_trigger,
+                    repeat: schedule.repeat,
+                };
+                state.schedule.set(new_schedule);
+                let generation = *next_generation;
+                *next_generation += 1;
+                state.generation.set(generation);
+                heap.push(HeapEntry {
+                    schedule: new_schedule,
+                    key,
+                    generation,
+                });
+                return Some(stacked_callback);
+            }
+            let stacked_callback = stack_callback(&state.timer);
+            timers.remove(key);
+            return Some(stacked_callback);
+        },
+    )
 }
 
 #[cfg(test)]
 mod test {
+    use std::cell::{Cell, RefCell};
     use std::ptr::null_mut;
+    use std::time::{Duration, Instant};
 
-    use durr::{now, Durr};
+    use durr::Durr;
 
-    use crate::scheduling::{State, STATE};
     use crate::Timer;
     use crate::{amx_arguments::VariadicAmxArguments, scheduling::reschedule_next_due_and_then};
 
-    use super::{insert_and_schedule_timer, Schedule};
+    use super::{insert_and_schedule_timer, Schedule, State, TimerContext};
+
+    /// A context whose clock only moves when the test tells it to, backed by its own
+    /// store instead of the production thread-local, so tests can assert time-dependent
+    /// firing deterministically.
+    struct MockContext {
+        now: Cell<Instant>,
+        store: RefCell<State>,
+    }
+
+    impl MockContext {
+        fn new() -> Self {
+            MockContext {
+                now: Cell::new(Instant::now()),
+                store: RefCell::new(State::new()),
+            }
+        }
+
+        fn advance(&self, duration: Duration) {
+            self.now.set(self.now.get() + duration);
+        }
+    }
+
+    impl TimerContext for MockContext {
+        fn now(&self) -> Instant {
+            self.now.get()
+        }
+
+        fn with_store<R>(&self, f: impl FnOnce(&mut State) -> R) -> R {
+            f(&mut self.store.borrow_mut())
+        }
+    }
 
     fn empty_timer() -> Timer {
         Timer {
@@ -158,39 +282,113 @@ mod test {
 
     fn noop(_timer: &Timer) {}
 
-    fn every_1s(key: usize) -> Schedule {
+    fn every_1s(ctx: &MockContext) -> Schedule {
         Schedule {
-            next_trigger: now() + (key as u64).seconds(),
+            next_trigger: ctx.now() + 1.seconds(),
             repeat: Some(1.seconds()),
         }
     }
 
-    fn dont_repeat(key: usize) -> Schedule {
-        Schedule {
-            next_trigger: now() + (key as u64).seconds(),
+    fn dont_repeat_in(offset: Duration) -> impl Fn(&MockContext) -> Schedule {
+        move |ctx| Schedule {
+            next_trigger: ctx.now() + offset,
             repeat: None,
         }
     }
 
-    fn timer_keys(q: &Vec<std::rc::Rc<super::TimerState>>) -> Vec<usize> {
-        dbg!(q);
-        q.iter().map(|s| s.key).collect()
+    #[test]
+    fn fires_only_once_due_time_is_reached() {
+        let ctx = MockContext::new();
+        assert_eq!(reschedule_next_due_and_then(&ctx, noop), None);
+
+        insert_and_schedule_timer(&ctx, empty_timer(), |_key| {
+            dont_repeat_in(2.seconds())(&ctx)
+        });
+        assert_eq!(reschedule_next_due_and_then(&ctx, noop), None);
+
+        ctx.advance(1.seconds());
+        assert_eq!(reschedule_next_due_and_then(&ctx, noop), None);
+
+        ctx.advance(1.seconds());
+        assert_eq!(reschedule_next_due_and_then(&ctx, noop), Some(()));
+        assert_eq!(reschedule_next_due_and_then(&ctx, noop), None);
     }
 
     #[test]
-    fn hello() {
-        assert_eq!(reschedule_next_due_and_then(now(), noop), None);
-        let first = insert_and_schedule_timer(empty_timer(), every_1s);
-        let second = insert_and_schedule_timer(empty_timer(), every_1s);
-        let third = insert_and_schedule_timer(empty_timer(), every_1s);
-        let fourth = insert_and_schedule_timer(empty_timer(), dont_repeat);
-        STATE.with_borrow_mut(|&mut State { ref mut queue, .. }| {
-            assert_eq!(timer_keys(queue), [fourth, third, second, first]);
+    fn repeating_timer_fires_once_per_advanced_second() {
+        let ctx = MockContext::new();
+        let fire_count = Cell::new(0usize);
+        let mark_fired = |_timer: &Timer| fire_count.set(fire_count.get() + 1);
+
+        insert_and_schedule_timer(&ctx, empty_timer(), |_key| every_1s(&ctx));
+
+        for expected_fires in 1..=3 {
+            ctx.advance(1.seconds());
+            assert_eq!(reschedule_next_due_and_then(&ctx, mark_fired), Some(()));
+            assert_eq!(reschedule_next_due_and_then(&ctx, mark_fired), None);
+            assert_eq!(fire_count.get(), expected_fires);
+        }
+    }
+
+    #[test]
+    fn deleting_a_timer_leaves_a_stale_heap_entry_that_is_skipped() {
+        let ctx = MockContext::new();
+        let first = insert_and_schedule_timer(&ctx, empty_timer(), |_key| {
+            dont_repeat_in(1.seconds())(&ctx)
+        });
+        let _second = insert_and_schedule_timer(&ctx, empty_timer(), |_key| {
+            dont_repeat_in(1.seconds())(&ctx)
+        });
+
+        super::delete_timer(&ctx, first).expect("timer should exist");
+
+        ctx.advance(1.seconds());
+        // Only the surviving timer fires; the stale heap entry for `first` is silently discarded.
+        assert_eq!(reschedule_next_due_and_then(&ctx, noop), Some(()));
+        assert_eq!(reschedule_next_due_and_then(&ctx, noop), None);
+    }
+
+    #[test]
+    fn reusing_a_freed_slab_key_does_not_confuse_stale_heap_entries() {
+        let ctx = MockContext::new();
+        let deleted = insert_and_schedule_timer(&ctx, empty_timer(), |_key| {
+            dont_repeat_in(1.seconds())(&ctx)
+        });
+        super::delete_timer(&ctx, deleted).expect("timer should exist");
+
+        // `Slab` hands back the freed key, so this is expected to land on `deleted`'s old key.
+        let reused_key = insert_and_schedule_timer(&ctx, empty_timer(), |_key| {
+            dont_repeat_in(3.seconds())(&ctx)
         });
-        assert!(reschedule_next_due_and_then(now(), noop).is_some());
-        STATE.with_borrow_mut(|&mut State { ref mut queue, .. }| {
-            assert_eq!(timer_keys(queue), [fourth, third, first, second]);
+        assert_eq!(
+            reused_key, deleted,
+            "this test only proves anything if the Slab key was actually reused"
+        );
+
+        ctx.advance(1.seconds());
+        // The deleted timer's stale heap entry (same key, due now) must not be mistaken
+        // for the new timer at this key, which isn't due for another 2s.
+        assert_eq!(reschedule_next_due_and_then(&ctx, noop), None);
+
+        ctx.advance(2.seconds());
+        assert_eq!(reschedule_next_due_and_then(&ctx, noop), Some(()));
+        assert_eq!(reschedule_next_due_and_then(&ctx, noop), None);
+    }
+
+    #[test]
+    fn rescheduling_a_timer_leaves_a_stale_heap_entry_that_is_skipped() {
+        let ctx = MockContext::new();
+        let key = insert_and_schedule_timer(&ctx, empty_timer(), |_key| {
+            dont_repeat_in(1.seconds())(&ctx)
         });
-        assert_eq!(reschedule_next_due_and_then(now(), noop), None);
+
+        super::reschedule_timer(&ctx, key, dont_repeat_in(2.seconds())(&ctx)).unwrap();
+
+        ctx.advance(1.seconds());
+        // The original 1s entry is now stale; the timer isn't actually due until 2s.
+        assert_eq!(reschedule_next_due_and_then(&ctx, noop), None);
+
+        ctx.advance(1.seconds());
+        assert_eq!(reschedule_next_due_and_then(&ctx, noop), Some(()));
     }
 }